@@ -0,0 +1,36 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use axum::Router;
+use tracing::info;
+
+/// where a server should listen: a TCP socket address, or a Unix domain socket path so it can
+/// sit behind an nginx/Caddy reverse proxy without exposing a TCP port
+#[derive(Debug, Clone)]
+pub enum BindTarget {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// serve `app` on `target`. TCP connections get `SocketAddr` connect-info; Unix peers have no
+/// address, so the service runs without connect-info in that case.
+pub async fn serve(target: BindTarget, app: Router) {
+    match target {
+        BindTarget::Tcp(addr) => {
+            info!("Listening on tcp://{addr}");
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        }
+        BindTarget::Unix(path) => {
+            info!("Listening on unix://{}", path.display());
+            // a stale socket file from a previous run would otherwise make bind fail
+            let _ = std::fs::remove_file(&path);
+            let listener = tokio::net::UnixListener::bind(&path).unwrap();
+            axum::serve(listener, app.into_make_service()).await.unwrap();
+        }
+    }
+}
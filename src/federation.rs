@@ -0,0 +1,186 @@
+use std::{collections::HashMap, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Extension, State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::roomy::SharedState;
+
+/// configuration for gossiping room snapshots between masterserver instances, turning a single
+/// `roomy` server into a mesh of cooperating ones
+#[derive(Clone)]
+pub struct FederationConfig {
+    /// identifies this mesh; peers reporting a different id are rejected at the handshake.
+    ///
+    /// this is a namespace tag, not a secret — it is exchanged in plaintext during the
+    /// handshake and provides no real authentication. only federate with peers you trust,
+    /// since any peer that knows (or guesses) this value can push arbitrary `Gossip` data
+    /// that gets merged straight into the room list served to clients.
+    pub network_id: String,
+    /// the URL this instance is reachable at, advertised to peers so their clients know where
+    /// to connect for rooms hosted here
+    pub public_url: String,
+    /// federation endpoints of peer masterservers, e.g. "ws://peer:8080/federation"
+    pub peers: Vec<String>,
+    /// how often to push a fresh room snapshot to each peer
+    pub gossip_interval: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum FederationProtocol {
+    /// sent immediately after connecting, and echoed back by the accepting peer; rejected if
+    /// `network_id` doesn't match
+    Hand { network_id: String, public: String },
+    /// a snapshot of the sender's locally known rooms, keyed by client id
+    Gossip { rooms: HashMap<Uuid, String> },
+}
+
+impl FederationProtocol {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|err| err.to_string())
+    }
+}
+
+/// the inbound half of federation: an axum route peers dial into to push their gossip to us.
+/// it only receives; our own rooms reach a peer over the connection we dial out to it
+pub fn router(config: FederationConfig) -> Router<SharedState> {
+    Router::new()
+        .route("/federation", get(inbound_handler))
+        .layer(Extension(config))
+}
+
+/// the outbound half of federation: dial every configured peer and keep gossiping with it
+pub fn spawn_peers(state: SharedState, config: FederationConfig) {
+    for peer in config.peers.clone() {
+        tokio::spawn(maintain_peer(state.clone(), config.clone(), peer));
+    }
+}
+
+async fn inbound_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<SharedState>,
+    Extension(config): Extension<FederationConfig>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| async move {
+        let (mut sink, mut stream) = socket.split();
+
+        let Some(Ok(Message::Text(text))) = stream.next().await else {
+            return;
+        };
+        let origin = match FederationProtocol::from_json(&text) {
+            Ok(FederationProtocol::Hand { network_id, public }) if network_id == config.network_id => public,
+            Ok(FederationProtocol::Hand { network_id, .. }) => {
+                warn!(
+                    "Federation: rejecting peer on network '{network_id}', expected '{}'",
+                    config.network_id
+                );
+                return;
+            }
+            _ => return,
+        };
+        info!("Federation: accepted peer {origin}");
+
+        let hand = FederationProtocol::Hand {
+            network_id: config.network_id.clone(),
+            public: config.public_url.clone(),
+        };
+        if sink.send(Message::Text(hand.to_json())).await.is_err() {
+            return;
+        }
+
+        while let Some(Ok(msg)) = stream.next().await {
+            let Message::Text(text) = msg else { continue };
+            if let Ok(FederationProtocol::Gossip { rooms }) = FederationProtocol::from_json(&text) {
+                state.remote.lock().await.insert(origin.clone(), rooms);
+            }
+        }
+
+        info!("Federation: peer {origin} disconnected");
+        state.remote.lock().await.remove(&origin);
+    })
+}
+
+/// dial a peer and keep gossiping with it, reconnecting with a fixed backoff if the connection
+/// drops or the handshake fails
+async fn maintain_peer(state: SharedState, config: FederationConfig, peer_url: String) {
+    loop {
+        if let Err(err) = gossip_with_peer(&state, &config, &peer_url).await {
+            warn!("Federation: peer {peer_url} error: {err}");
+        }
+        tokio::time::sleep(config.gossip_interval).await;
+    }
+}
+
+async fn gossip_with_peer(
+    state: &SharedState,
+    config: &FederationConfig,
+    peer_url: &str,
+) -> Result<(), String> {
+    let (mut ws, _) = connect_async(peer_url).await.map_err(|err| err.to_string())?;
+    info!("Federation: connected to peer {peer_url}");
+
+    let hand = FederationProtocol::Hand {
+        network_id: config.network_id.clone(),
+        public: config.public_url.clone(),
+    };
+    ws.send(WsMessage::Text(hand.to_json()))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let Some(Ok(WsMessage::Text(text))) = ws.next().await else {
+        return Err("peer closed before handshake reply".to_string());
+    };
+    let origin = match FederationProtocol::from_json(&text)? {
+        FederationProtocol::Hand { network_id, public } if network_id == config.network_id => public,
+        FederationProtocol::Hand { network_id, .. } => {
+            return Err(format!(
+                "peer is on network '{network_id}', expected '{}'",
+                config.network_id
+            ))
+        }
+        _ => return Err("expected a handshake reply".to_string()),
+    };
+
+    let mut ticker = tokio::time::interval(config.gossip_interval);
+    let result = loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let rooms = state.infos.lock().await.clone();
+                let gossip = FederationProtocol::Gossip { rooms };
+                if let Err(err) = ws.send(WsMessage::Text(gossip.to_json())).await {
+                    break Err(err.to_string());
+                }
+            }
+            msg = ws.next() => {
+                match msg {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        if let Ok(FederationProtocol::Gossip { rooms }) = FederationProtocol::from_json(&text) {
+                            state.remote.lock().await.insert(origin.clone(), rooms);
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    _ => break Err("peer connection closed".to_string()),
+                }
+            }
+        }
+    };
+
+    state.remote.lock().await.remove(&origin);
+    result
+}
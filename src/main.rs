@@ -1,9 +1,18 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
 use tracing::{debug, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+mod bind;
+mod federation;
 mod matchbox;
 mod roomy;
 
+use bind::BindTarget;
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -19,8 +28,22 @@ async fn main() {
     let matchbox_port = 8081;
     info!("Starting Roomy on port {roomy_port} and Matchbox on port {matchbox_port}");
     debug!("Debug enabled");
-    let roomy_server = tokio::spawn(roomy::start(roomy_port));
-    let matcbox_server = tokio::spawn(matchbox::start(matchbox_port));
+    let roomy_bind = BindTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), roomy_port));
+    let matchbox_bind = BindTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), matchbox_port));
+    let roomy_idle_timeout = Duration::from_secs(30);
+    let roomy_scan_interval = Duration::from_secs(10);
+    let roomy_broadcast_queue_len = 32;
+    // no peer masterservers configured by default; set this to federate with a mesh of them
+    let roomy_federation = None;
+    let roomy_server = tokio::spawn(roomy::start(
+        roomy_bind,
+        roomy_idle_timeout,
+        roomy_scan_interval,
+        roomy_broadcast_queue_len,
+        roomy_federation,
+    ));
+    let matchbox_matchmaking_timeout = Duration::from_secs(60);
+    let matcbox_server = tokio::spawn(matchbox::start(matchbox_bind, matchbox_matchmaking_timeout));
     let _ = roomy_server.await;
     let _ = matcbox_server.await;
 }
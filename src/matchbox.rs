@@ -1,4 +1,3 @@
-use std::net::{IpAddr, Ipv4Addr};
 use matchbox_signaling::{SignalingServer, SignalingServerBuilder};
 use axum::{async_trait, extract::ws::Message, Error};
 use matchbox_protocol::PeerId;
@@ -6,12 +5,14 @@ use matchbox_signaling::{
     common_logic::{self, StateObj},
     SignalingError, SignalingState,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Default, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct RoomId(pub String);
@@ -22,73 +23,152 @@ pub(crate) struct RequestedRoom {
     pub next: Option<usize>,
 }
 
+/// a room a client asked to join, plus the reconnect token it presented (if any) so a peer
+/// dropping mid-match can rejoin instead of starting a fresh one
+#[derive(Debug, Clone)]
+pub(crate) struct WaitingClient {
+    pub room: RequestedRoom,
+    pub reconnect_token: Option<Uuid>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Peer {
     pub uuid: PeerId,
     pub room: RequestedRoom,
     pub sender: UnboundedSender<Result<Message, Error>>,
+    /// presented by the client on future connections to rejoin this same room
+    pub reconnect_token: Uuid,
+}
+
+/// sent to a peer right after it connects, so it can later present the same value as
+/// `?reconnect_token=` to rejoin this room. `JsonPeerEvent` comes from `matchbox_protocol` and
+/// has no room for custom fields, so this travels as its own small JSON frame.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReconnectTokenEvent {
+    reconnect_token: Uuid,
+}
+
+/// lifecycle of a matchmaking room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RoomStatus {
+    /// still waiting for `RequestedRoom::next` peers to join
+    Filling,
+    /// reached its target peer count; a match is in progress
+    Complete,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Room {
+    pub peers: HashSet<PeerId>,
+    pub status: RoomStatus,
+    pub created_at: Instant,
+    /// peers that have ever joined this room, keyed by the reconnect token they were issued
+    pub reconnect_tokens: HashMap<Uuid, PeerId>,
 }
 
-#[derive(Default, Debug, Clone)]
+impl Room {
+    fn new() -> Self {
+        Self {
+            peers: HashSet::new(),
+            status: RoomStatus::Filling,
+            created_at: Instant::now(),
+            reconnect_tokens: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct ServerState {
-    clients_waiting: StateObj<HashMap<SocketAddr, RequestedRoom>>,
-    clients_in_queue: StateObj<HashMap<PeerId, RequestedRoom>>,
+    clients_waiting: StateObj<HashMap<SocketAddr, WaitingClient>>,
+    clients_in_queue: StateObj<HashMap<PeerId, WaitingClient>>,
     clients: StateObj<HashMap<PeerId, Peer>>,
-    rooms: StateObj<HashMap<RequestedRoom, HashSet<PeerId>>>,
+    rooms: StateObj<HashMap<RequestedRoom, Room>>,
+    /// how long a `Filling` room may wait for the rest of its players before it is torn down
+    matchmaking_timeout: Duration,
 }
 impl SignalingState for ServerState {}
 
 impl ServerState {
+    pub fn new(matchmaking_timeout: Duration) -> Self {
+        Self {
+            clients_waiting: Default::default(),
+            clients_in_queue: Default::default(),
+            clients: Default::default(),
+            rooms: Default::default(),
+            matchmaking_timeout,
+        }
+    }
+
     /// Add a waiting client to matchmaking
-    pub fn add_waiting_client(&mut self, origin: SocketAddr, room: RequestedRoom) {
-        self.clients_waiting.lock().unwrap().insert(origin, room);
+    pub fn add_waiting_client(
+        &mut self,
+        origin: SocketAddr,
+        room: RequestedRoom,
+        reconnect_token: Option<Uuid>,
+    ) {
+        self.clients_waiting
+            .lock()
+            .unwrap()
+            .insert(origin, WaitingClient { room, reconnect_token });
     }
 
     /// Assign a peer id to a waiting client
     pub fn assign_id_to_waiting_client(&mut self, origin: SocketAddr, peer_id: PeerId) {
-        let room = {
+        let waiting = {
             let mut lock = self.clients_waiting.lock().unwrap();
             lock.remove(&origin).expect("waiting client")
         };
         {
             let mut lock = self.clients_in_queue.lock().unwrap();
-            lock.insert(peer_id, room);
+            lock.insert(peer_id, waiting);
         }
     }
 
-    /// Remove the waiting peer, returning the peer's requested room
-    pub fn remove_waiting_peer(&mut self, peer_id: PeerId) -> RequestedRoom {
-        let room = {
+    /// Remove the waiting peer, returning its requested room and reconnect token
+    pub fn remove_waiting_peer(&mut self, peer_id: PeerId) -> WaitingClient {
+        let waiting = {
             let mut lock = self.clients_in_queue.lock().unwrap();
             lock.remove(&peer_id).expect("waiting peer")
         };
-        room
+        waiting
     }
 
-    /// Add a peer, returning the peers already in room
-    pub fn add_peer(&mut self, peer: Peer) -> Vec<PeerId> {
+    /// Add a peer, returning the peers already in the room so the caller can introduce them
+    pub fn add_peer(&mut self, peer: Peer, requested_token: Option<Uuid>) -> Vec<PeerId> {
         let peer_id = peer.uuid;
-        let room = peer.room.clone();
+        let room_key = peer.room.clone();
+        let reconnect_token = peer.reconnect_token;
         {
             let mut clients = self.clients.lock().unwrap();
             clients.insert(peer.uuid, peer);
         };
+
         let mut rooms = self.rooms.lock().unwrap();
-        let peers = rooms.entry(room.clone()).or_default();
-        let prev_peers = peers.iter().cloned().collect();
+        let room = rooms.entry(room_key.clone()).or_insert_with(Room::new);
 
-        match room.next {
-            None => {
-                peers.insert(peer_id);
+        let is_rejoin = requested_token.is_some_and(|token| room.reconnect_tokens.contains_key(&token));
+        if room.status == RoomStatus::Complete && !is_rejoin {
+            // previous match finished; a fresh (non-rejoining) request for this room id starts
+            // a new one rather than joining the finished match
+            *room = Room::new();
+        }
+        if is_rejoin {
+            // the rejoining peer replaces its earlier connection rather than being counted twice
+            if let Some(previous) = requested_token.and_then(|token| room.reconnect_tokens.get(&token).copied()) {
+                room.peers.remove(&previous);
             }
-            Some(num_players) => {
-                if peers.len() == num_players - 1 {
-                    peers.clear(); // room is complete
-                } else {
-                    peers.insert(peer_id);
-                }
+        }
+
+        let prev_peers = room.peers.iter().cloned().collect();
+        room.reconnect_tokens.insert(reconnect_token, peer_id);
+        room.peers.insert(peer_id);
+
+        if let Some(num_players) = room_key.next {
+            if room.status == RoomStatus::Filling && room.peers.len() == num_players {
+                room.status = RoomStatus::Complete;
             }
-        };
+        }
 
         prev_peers
     }
@@ -105,23 +185,26 @@ impl ServerState {
             .lock()
             .unwrap()
             .get(room)
-            .map(|room_peers| room_peers.iter().copied().collect::<Vec<PeerId>>())
+            .map(|room| room.peers.iter().copied().collect::<Vec<PeerId>>())
             .unwrap_or_default()
     }
 
-    /// Remove a peer from the state if it existed, returning the peer removed.
+    /// Remove a peer from the state if it existed, returning the peer removed. If the peer was
+    /// its room's last occupant, the room itself is torn down, regardless of whether it was
+    /// still `Filling` or had already gone `Complete` — otherwise every room that ever filled up
+    /// would linger in the map forever.
     #[must_use]
     pub fn remove_peer(&mut self, peer_id: &PeerId) -> Option<Peer> {
         let peer = { self.clients.lock().unwrap().remove(peer_id) };
 
         if let Some(ref peer) = peer {
-            // Best effort to remove peer from their room
-            _ = self
-                .rooms
-                .lock()
-                .unwrap()
-                .get_mut(&peer.room)
-                .map(|room| room.remove(peer_id));
+            let mut rooms = self.rooms.lock().unwrap();
+            if let Some(room) = rooms.get_mut(&peer.room) {
+                room.peers.remove(peer_id);
+                if room.peers.is_empty() {
+                    rooms.remove(&peer.room);
+                }
+            }
         }
         peer
     }
@@ -134,8 +217,32 @@ impl ServerState {
             None => Err(SignalingError::UnknownPeer),
         }
     }
-}
 
+    /// Tear down every `Filling` room that has been waiting longer than `matchmaking_timeout`,
+    /// returning the peers that were waiting in them so the caller can notify them.
+    pub fn reap_stale_rooms(&mut self) -> Vec<Peer> {
+        let matchmaking_timeout = self.matchmaking_timeout;
+        let mut clients = self.clients.lock().unwrap();
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut abandoned = Vec::new();
+
+        rooms.retain(|room_id, room| {
+            let stale =
+                room.status == RoomStatus::Filling && room.created_at.elapsed() > matchmaking_timeout;
+            if stale {
+                warn!("Room {room_id:?} timed out waiting to fill, tearing down");
+                for peer_id in room.peers.drain() {
+                    if let Some(peer) = clients.remove(&peer_id) {
+                        abandoned.push(peer);
+                    }
+                }
+            }
+            !stale
+        });
+
+        abandoned
+    }
+}
 
 use futures::StreamExt;
 use matchbox_protocol::{JsonPeerEvent, PeerRequest};
@@ -158,15 +265,24 @@ impl SignalingTopology<NoCallbacks, ServerState> for MatchmakingDemoTopology {
             ..
         } = upgrade;
 
-        let room = state.remove_waiting_peer(peer_id);
+        let waiting = state.remove_waiting_peer(peer_id);
+        let reconnect_token = waiting.reconnect_token.unwrap_or_else(Uuid::new_v4);
         let peer = Peer {
             uuid: peer_id,
             sender: sender.clone(),
-            room,
+            room: waiting.room,
+            reconnect_token,
         };
 
+        // Tell the peer which token to present to rejoin this room if it drops later.
+        let token_event =
+            Message::Text(serde_json::to_string(&ReconnectTokenEvent { reconnect_token }).unwrap());
+        if let Err(e) = sender.send(Ok(token_event)) {
+            error!("error sending reconnect token to {peer_id:?}: {e:?}");
+        }
+
         // Tell other waiting peers about me!
-        let peers = state.add_peer(peer);
+        let peers = state.add_peer(peer, waiting.reconnect_token);
         let event_text = JsonPeerEvent::NewPeer(peer_id).to_string();
         let event = Message::Text(event_text.clone());
         for peer_id in peers {
@@ -244,17 +360,20 @@ impl SignalingTopology<NoCallbacks, ServerState> for MatchmakingDemoTopology {
     }
 }
 
-
-
-
-pub async fn start() {
+/// Start the matchmaking signaling server on `bind`.
+///
+/// `matchbox_signaling::SignalingServerBuilder` only knows how to listen on a TCP
+/// `SocketAddr` today, so a `BindTarget::Unix` cannot actually be served; rather than
+/// come up without a listener, this panics so the gap is obvious at startup instead of a
+/// silently dead server.
+pub async fn start(bind: crate::bind::BindTarget, matchmaking_timeout: Duration) {
     // todo needs to implement room support
     /*println!("Starting 'matchbox'...");
     let server = SignalingServer::client_server_builder((Ipv4Addr::UNSPECIFIED, 8081))
     .on_connection_request(|c| {
         Ok(true) // Allow all connections
     })
-    
+
     .on_id_assignment(|(socket, id)| println!("{socket} received {id}"))
     .on_host_connected(|id| println!("Host joined: {id}"))
     .on_host_disconnected(|id| println!("Host left: {id}"))
@@ -264,9 +383,34 @@ pub async fn start() {
     .build();
     let _ = server.serve().await;*/
 
+    let addr = match bind {
+        crate::bind::BindTarget::Tcp(addr) => addr,
+        crate::bind::BindTarget::Unix(path) => {
+            panic!(
+                "matchbox_signaling only supports binding to a TCP address; cannot bind unix://{}",
+                path.display()
+            );
+        }
+    };
+
     info!("hell world");
-    let mut state = ServerState::default();
-    let server = SignalingServerBuilder::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8081), MatchmakingDemoTopology::default(), state.clone())
+    let mut state = ServerState::new(matchmaking_timeout);
+
+    // periodically tear down rooms that have been waiting too long to fill
+    tokio::spawn({
+        let mut state = state.clone();
+        async move {
+            let mut ticker = tokio::time::interval(matchmaking_timeout.min(Duration::from_secs(5)));
+            loop {
+                ticker.tick().await;
+                for peer in state.reap_stale_rooms() {
+                    let _ = peer.sender.send(Ok(Message::Close(None)));
+                }
+            }
+        }
+    });
+
+    let server = SignalingServerBuilder::new(addr, MatchmakingDemoTopology::default(), state.clone())
         .on_connection_request({
             let mut state = state.clone();
             move |connection| {
@@ -275,8 +419,12 @@ pub async fn start() {
                     .query_params
                     .get("next")
                     .and_then(|next| next.parse::<usize>().ok());
+                let reconnect_token = connection
+                    .query_params
+                    .get("reconnect_token")
+                    .and_then(|token| Uuid::parse_str(token).ok());
                 let room = RequestedRoom { id: room_id, next };
-                state.add_waiting_client(connection.origin, room);
+                state.add_waiting_client(connection.origin, room, reconnect_token);
                 Ok(true) // allow all clients
             }
         })
@@ -294,4 +442,88 @@ pub async fn start() {
         .serve()
         .await
         .expect("Unable to run signaling server, is it already running?")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(id: &str, next: Option<usize>) -> RequestedRoom {
+        RequestedRoom {
+            id: RoomId(id.to_string()),
+            next,
+        }
+    }
+
+    fn peer(room: RequestedRoom, reconnect_token: Uuid) -> Peer {
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        Peer {
+            uuid: PeerId(Uuid::new_v4()),
+            room,
+            sender,
+            reconnect_token,
+        }
+    }
+
+    #[test]
+    fn room_fills_and_completes() {
+        let mut state = ServerState::new(Duration::from_secs(60));
+        let room_key = room("lobby", Some(2));
+
+        state.add_peer(peer(room_key.clone(), Uuid::new_v4()), None);
+        assert_eq!(state.get_room_peers(&room_key).len(), 1);
+
+        state.add_peer(peer(room_key.clone(), Uuid::new_v4()), None);
+        assert_eq!(state.get_room_peers(&room_key).len(), 2);
+
+        let rooms = state.rooms.lock().unwrap();
+        assert_eq!(rooms.get(&room_key).unwrap().status, RoomStatus::Complete);
+    }
+
+    #[test]
+    fn rejoin_with_a_known_token_keeps_the_finished_room() {
+        let mut state = ServerState::new(Duration::from_secs(60));
+        let room_key = room("lobby", Some(1));
+        let token = Uuid::new_v4();
+
+        state.add_peer(peer(room_key.clone(), token), None);
+        assert_eq!(
+            state.rooms.lock().unwrap().get(&room_key).unwrap().status,
+            RoomStatus::Complete
+        );
+
+        // a later request presenting the same token is a rejoin, not a fresh match
+        state.add_peer(peer(room_key.clone(), token), Some(token));
+        assert_eq!(state.get_room_peers(&room_key).len(), 1);
+    }
+
+    #[test]
+    fn fresh_request_for_a_finished_room_starts_a_new_match() {
+        let mut state = ServerState::new(Duration::from_secs(60));
+        let room_key = room("lobby", Some(1));
+
+        state.add_peer(peer(room_key.clone(), Uuid::new_v4()), None);
+        assert_eq!(
+            state.rooms.lock().unwrap().get(&room_key).unwrap().status,
+            RoomStatus::Complete
+        );
+
+        // no reconnect token presented, so this should reset the room rather than join the
+        // finished match
+        state.add_peer(peer(room_key.clone(), Uuid::new_v4()), None);
+        assert_eq!(state.get_room_peers(&room_key).len(), 1);
+    }
+
+    #[test]
+    fn removing_the_last_peer_deletes_the_room() {
+        let mut state = ServerState::new(Duration::from_secs(60));
+        let room_key = room("lobby", Some(2));
+        let peer_a = peer(room_key.clone(), Uuid::new_v4());
+        let peer_a_id = peer_a.uuid;
+
+        state.add_peer(peer_a, None);
+        state.remove_peer(&peer_a_id);
+
+        assert!(state.rooms.lock().unwrap().get(&room_key).is_none());
+    }
+}
@@ -1,23 +1,78 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
     },
     response::IntoResponse,
     routing::get,
     Router,
 };
-use futures::{stream::SplitSink, SinkExt, StreamExt};
+use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-use tracing::info;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
 use uuid::Uuid;
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct SharedState {
-    pub sinks: Arc<Mutex<HashMap<Uuid, SplitSink<WebSocket, Message>>>>,
+    /// per-client writer queue, drained by that client's dedicated forwarding task
+    pub writers: Arc<Mutex<HashMap<Uuid, ClientWriter>>>,
+    /// most recently broadcast `info` per connected client, used to answer `ListRooms`
+    pub infos: Arc<Mutex<HashMap<Uuid, String>>>,
+    /// time each client was last heard from, used to evict idle connections
+    pub last_seen: Arc<Mutex<HashMap<Uuid, Instant>>>,
+    /// bound of each client's writer queue; a client slower than this is dropped rather than
+    /// stalling the broadcaster
+    pub broadcast_queue_len: usize,
+    /// room snapshots gossiped in from federated peers, keyed by the peer's public URL
+    pub remote: Arc<Mutex<HashMap<String, HashMap<Uuid, String>>>>,
+}
+
+impl SharedState {
+    pub fn new(broadcast_queue_len: usize) -> Self {
+        Self {
+            writers: Default::default(),
+            infos: Default::default(),
+            last_seen: Default::default(),
+            broadcast_queue_len,
+            remote: Default::default(),
+        }
+    }
+}
+
+/// a connected client's queue, plus which wire format it negotiated at upgrade time
+#[derive(Clone)]
+pub(crate) struct ClientWriter {
+    tx: mpsc::Sender<Message>,
+    binary: bool,
+    /// aborts the connection task that owns this client's socket. That task holds its own
+    /// clone of `tx` for the lifetime of the connection, so dropping this `ClientWriter` alone
+    /// never closes the task or its socket — the abort handle is what actually does.
+    forwarder: tokio::task::AbortHandle,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WsQuery {
+    /// pass `?format=binary` at upgrade time to use the bincode transport instead of JSON
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomEntry {
+    pub id: Uuid,
+    pub info: String,
+    /// the federated peer this room was gossiped in from, so clients know which backend to
+    /// connect to; `None` means the room is local to this instance
+    #[serde(default)]
+    pub origin: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,11 +85,20 @@ pub enum Protocol {
     Welcome { id: Uuid },
     /// received by both clients and the server
     /// sent by a client and distributed to other clients to let them know about 'self'.
-    /// info contains client provided information, e.g. name of a multiplayer room name, number of players, etc, which is application dependend.  
+    /// info contains client provided information, e.g. name of a multiplayer room name, number of players, etc, which is application dependend.
     Broadcast { id: Uuid, info: String },
     /// sent by clients to server
     /// keeps the connection alive
     Keepalive,
+    /// sent by a client to request a snapshot of all currently known rooms
+    ListRooms,
+    /// received by a client in response to `ListRooms`, and automatically right after `Welcome`.
+    /// entries contains the most recently broadcast info for every connected client, followed by
+    /// the rooms gossiped in from federated peers (tagged with their `origin`)
+    RoomList { entries: Vec<RoomEntry> },
+    /// broadcast by the server when a client disconnects, including idle clients evicted by the
+    /// idle timeout and slow clients dropped for falling behind on broadcasts
+    Disconnected { id: Uuid },
 }
 
 impl Protocol {
@@ -48,9 +112,124 @@ impl Protocol {
             Err(err) => Err(err.to_string()),
         }
     }
+
+    /// compact binary encoding of a single message, used by the `format=binary` transport
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        bincode::deserialize(bytes).map_err(|err| err.to_string())
+    }
+
+    /// encode several messages into one length-delimited buffer so they can be batched into a
+    /// single binary websocket frame
+    pub fn to_bytes_batch(messages: &[Protocol]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for msg in messages {
+            let bytes = msg.to_bytes();
+            buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&bytes);
+        }
+        buf
+    }
+
+    /// decode a length-delimited buffer produced by `to_bytes_batch` back into messages
+    pub fn from_bytes_batch(bytes: &[u8]) -> Result<Vec<Self>, String> {
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= bytes.len() {
+            let len = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            offset += 4;
+            if offset + len > bytes.len() {
+                return Err("truncated length-delimited frame".to_string());
+            }
+            messages.push(Self::from_bytes(&bytes[offset..offset + len])?);
+            offset += len;
+        }
+        Ok(messages)
+    }
+}
+
+/// encode a single message for the wire, honoring the client's negotiated format
+fn encode(msg: &Protocol, binary: bool) -> Message {
+    if binary {
+        Message::Binary(Protocol::to_bytes_batch(std::slice::from_ref(msg)))
+    } else {
+        Message::Text(msg.to_json())
+    }
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<SharedState>) -> impl IntoResponse {
+/// build a `RoomList` snapshot from the info most recently broadcast by each connected client,
+/// merged with the latest snapshot gossiped in from every federated peer
+async fn room_list(state: &SharedState) -> Protocol {
+    let mut entries: Vec<RoomEntry> = state
+        .infos
+        .lock()
+        .await
+        .iter()
+        .map(|(id, info)| RoomEntry {
+            id: *id,
+            info: info.clone(),
+            origin: None,
+        })
+        .collect();
+
+    for (origin, rooms) in state.remote.lock().await.iter() {
+        entries.extend(rooms.iter().map(|(id, info)| RoomEntry {
+            id: *id,
+            info: info.clone(),
+            origin: Some(origin.clone()),
+        }));
+    }
+
+    Protocol::RoomList { entries }
+}
+
+/// fan a message out to every client's writer queue without blocking; a client whose queue is
+/// full is considered too slow and gets dropped rather than stalling everyone else
+async fn broadcast(state: &SharedState, msg: &Protocol) {
+    let stalled: Vec<Uuid> = {
+        let writers = state.writers.lock().await;
+        writers
+            .iter()
+            .filter(|(_, writer)| writer.tx.try_send(encode(msg, writer.binary)).is_err())
+            .map(|(id, _)| *id)
+            .collect()
+    };
+
+    for client_id in stalled {
+        warn!("Client {} fell behind on broadcasts, dropping", client_id);
+        // boxed: disconnect_client broadcasts the departure, so the two functions recurse
+        Box::pin(disconnect_client(state, client_id)).await;
+    }
+}
+
+/// remove a client from all tracking maps and let the rest of the server know it left, then
+/// reclaim its connection task. The abort is the last step (not the first): this function is
+/// itself called from inside that same task on a normal disconnect, and self-aborting any
+/// earlier would cut off the cleanup below the moment it hit its next `.await`. Calling it from
+/// a different task entirely — the slow-client and idle-client eviction paths — is the case this
+/// exists for: those only had a `ClientWriter` clone to go on, with no other way to stop the
+/// connection's task (and therefore its socket) from running forever.
+async fn disconnect_client(state: &SharedState, client_id: Uuid) {
+    let writer = state.writers.lock().await.remove(&client_id);
+    state.infos.lock().await.remove(&client_id);
+    state.last_seen.lock().await.remove(&client_id);
+    let Some(writer) = writer else {
+        return;
+    };
+    info!("Client {} disconnected", client_id);
+    broadcast(state, &Protocol::Disconnected { id: client_id }).await;
+    writer.forwarder.abort();
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Query(query): Query<WsQuery>,
+    State(state): State<SharedState>,
+) -> impl IntoResponse {
+    let binary = query.format.as_deref() == Some("binary");
     ws.on_upgrade(move |socket| async move {
         let (mut sink, mut stream) = socket.split();
         // new client connected.
@@ -58,60 +237,181 @@ async fn ws_handler(ws: WebSocketUpgrade, State(state): State<SharedState>) -> i
         // the client can use this id to send broadcasts to other connected clients
         let client_id = Uuid::new_v4();
         info!("Client {} connected", client_id);
+        state.last_seen.lock().await.insert(client_id, Instant::now());
         if sink
-            .send(Message::Text(Protocol::Welcome { id: client_id }.to_json()))
+            .send(encode(&Protocol::Welcome { id: client_id }, binary))
             .await
             .is_err()
         {
             return;
         }
 
-        // move sink to list of sinks such that broadcasts can be sent to all connected clients
-        {
-            let mut sinks = state.sinks.lock().await;
-            sinks.insert(client_id, sink);
+        // send the new client a snapshot of all rooms known so far, same as a ListRooms reply
+        let snapshot = room_list(&state).await;
+        if sink.send(encode(&snapshot, binary)).await.is_err() {
+            return;
         }
-        // wait for messages
-        while let Some(msg) = stream.next().await {
-            let Ok(msg) = msg else { break };
-            let Ok(json) = msg.to_text() else { break };
-            let Ok(msg) = Protocol::from_json(json) else {
-                break;
-            };
-            match msg {
-                Protocol::Broadcast { info, .. } => {
-                    // forward message to other clients (including self)
-                    let msg = Protocol::Broadcast {
-                        id: client_id,
-                        info,
-                    };
-                    let mut sinks = state.sinks.lock().await;
-                    let json = msg.to_json();
-                    for sink in sinks.values_mut() {
-                        let _ = sink.send(Message::Text(json.clone())).await;
+
+        // broadcasting becomes a non-blocking try_send into this client's queue instead of an
+        // await on its socket
+        let (tx, mut rx) = mpsc::channel::<Message>(state.broadcast_queue_len);
+
+        // a single task owns both halves of the split socket, select!ing between inbound frames
+        // and outbound broadcasts. that's what lets `disconnect_client` actually reclaim the
+        // connection by aborting this task, even when called from a different task entirely
+        // (the slow-client and idle-client eviction paths) — splitting the read and write sides
+        // across two independently-owned tasks left the reader's half with no way to be told to
+        // stop, so it (and the socket) leaked for the life of the server.
+        let task_state = state.clone();
+        let task_tx = tx.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    outgoing = rx.recv() => {
+                        let Some(outgoing) = outgoing else { break };
+                        if sink.send(outgoing).await.is_err() {
+                            break;
+                        }
+                    }
+                    incoming = stream.next() => {
+                        let Some(Ok(msg)) = incoming else { break };
+                        // Text frames carry JSON, Binary frames carry one or more
+                        // length-delimited bincode messages
+                        let messages: Vec<Protocol> = match msg {
+                            Message::Text(text) => match Protocol::from_json(&text) {
+                                Ok(msg) => vec![msg],
+                                Err(_) => break,
+                            },
+                            Message::Binary(bytes) => match Protocol::from_bytes_batch(&bytes) {
+                                Ok(messages) => messages,
+                                Err(_) => break,
+                            },
+                            _ => continue,
+                        };
+                        task_state.last_seen.lock().await.insert(client_id, Instant::now());
+
+                        for msg in messages {
+                            match msg {
+                                Protocol::Broadcast { info, .. } => {
+                                    // remember the client's latest info so it can be served by ListRooms
+                                    task_state.infos.lock().await.insert(client_id, info.clone());
+
+                                    // forward message to other clients (including self)
+                                    let msg = Protocol::Broadcast {
+                                        id: client_id,
+                                        info,
+                                    };
+                                    broadcast(&task_state, &msg).await;
+                                }
+                                Protocol::ListRooms => {
+                                    let snapshot = room_list(&task_state).await;
+                                    let _ = task_tx.try_send(encode(&snapshot, binary));
+                                }
+                                _ => {}
+                            }
+                        }
                     }
                 }
-                _ => {}
             }
-        }
 
-        // connection ended, remove sink from sinks
-        let mut sinks = state.sinks.lock().await;
-        sinks.remove(&client_id);
-        info!("Client {} disconnected", client_id);
+            disconnect_client(&task_state, client_id).await;
+        });
+
+        state.writers.lock().await.insert(
+            client_id,
+            ClientWriter {
+                tx,
+                binary,
+                forwarder: task.abort_handle(),
+            },
+        );
     })
 }
 
-pub async fn start() {
+/// periodically scans `last_seen` and evicts clients that have exceeded `idle_timeout`,
+/// tearing down their connection task and socket and broadcasting their departure to everyone
+/// still connected. This is exactly the caller `disconnect_client` had to learn to support
+/// aborting a connection it doesn't own: a timed-out client is parked on its own socket read,
+/// so only an external abort (not just dropping bookkeeping) actually reclaims it.
+async fn evict_idle_clients(state: SharedState, idle_timeout: Duration, scan_interval: Duration) {
+    let mut ticker = tokio::time::interval(scan_interval);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let idle_clients: Vec<Uuid> = state
+            .last_seen
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, last_seen)| now.duration_since(**last_seen) > idle_timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for client_id in idle_clients {
+            info!("Client {} timed out, evicting", client_id);
+            disconnect_client(&state, client_id).await;
+        }
+    }
+}
+
+pub async fn start(
+    bind: crate::bind::BindTarget,
+    idle_timeout: Duration,
+    scan_interval: Duration,
+    broadcast_queue_len: usize,
+    federation: Option<crate::federation::FederationConfig>,
+) {
     info!("Starting 'roomy'...");
-    let app = Router::new()
-        .route("/", get(ws_handler))
-        .with_state(SharedState::default());
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+    let state = SharedState::new(broadcast_queue_len);
+    tokio::spawn(evict_idle_clients(state.clone(), idle_timeout, scan_interval));
+
+    let mut app = Router::new().route("/", get(ws_handler));
+    if let Some(federation) = federation {
+        app = app.merge(crate::federation::router(federation.clone()));
+        crate::federation::spawn_peers(state.clone(), federation);
+    }
+    let app = app.with_state(state);
+
+    crate::bind::serve(bind, app).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_messages() -> Vec<Protocol> {
+        vec![
+            Protocol::Welcome { id: Uuid::new_v4() },
+            Protocol::Broadcast {
+                id: Uuid::new_v4(),
+                info: "2/4 players".to_string(),
+            },
+            Protocol::Keepalive,
+        ]
+    }
+
+    #[test]
+    fn batch_round_trips_through_bytes() {
+        let messages = sample_messages();
+        let bytes = Protocol::to_bytes_batch(&messages);
+        let decoded = Protocol::from_bytes_batch(&bytes).unwrap();
+
+        assert_eq!(decoded.len(), messages.len());
+        for (original, decoded) in messages.iter().zip(decoded.iter()) {
+            assert_eq!(original.to_json(), decoded.to_json());
+        }
+    }
+
+    #[test]
+    fn empty_batch_decodes_to_no_messages() {
+        let bytes = Protocol::to_bytes_batch(&[]);
+        assert!(Protocol::from_bytes_batch(&bytes).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_batch_is_rejected() {
+        let bytes = Protocol::to_bytes_batch(&sample_messages());
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(Protocol::from_bytes_batch(truncated).is_err());
+    }
 }